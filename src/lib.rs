@@ -3,6 +3,7 @@
 #[macro_use]
 extern crate combine;
 
+use std::cell::RefCell;
 use std::collections::HashMap;
 use std::rc::Rc;
 
@@ -19,10 +20,36 @@ pub enum Value {
     Void,
     False,
     Int(u64),
-    Function(Vec<String>, Vec<Ast>),
-    InbuiltFunc(fn(Vec<Value>) -> Value),
+    Float(f64),
+    // The `Rc<Scope>` is the environment the lambda literal was evaluated
+    // in, captured so the closure keeps seeing those bindings no matter
+    // where it's later called from or returned to — including after the
+    // call that created it has returned, e.g. a function that builds and
+    // returns another function closing over its argument. A `Define` that
+    // stores a closure back into the scope it captured (`(= f (\ ...))`)
+    // does form an `Rc` cycle, but for a small tree-walking interpreter
+    // that's a deliberate trade: leaking that one cycle is cheaper than
+    // breaking every closure that legitimately outlives its defining call.
+    Function(Vec<String>, Vec<Ast>, Rc<Scope>),
+    InbuiltFunc(fn(Vec<Value>) -> Result<Value, EvalError>),
 }
 
+/// Everything that can go wrong while evaluating an `Ast`, in place of the
+/// `panic!`s `eval` used to reach for.
+#[derive(Debug, Clone, PartialEq)]
+pub enum EvalError {
+    UnknownVariable(String),
+    NotCallable,
+    ArityMismatch { expected: usize, got: usize },
+    TypeError(&'static str),
+}
+
+/// An opt-in hook consulted before the normal scope lookup for every
+/// `Variable`. Lets an embedder serve dynamically computed or lazily
+/// materialized globals (host data, constants) without pre-populating a
+/// `Scope`, and short-circuits lookups for names it knows are hot.
+pub type Resolver = Rc<dyn Fn(&str) -> Option<Value>>;
+
 impl PartialEq for Value {
     fn eq(&self, other: &Self) -> bool {
         use Value::*;
@@ -31,70 +58,366 @@ impl PartialEq for Value {
             (&Void, &Void) => true,
             (&False, &False) => true,
             (&Int(a), &Int(b)) => a == b,
+            (&Float(a), &Float(b)) => a == b,
+            (&Int(a), &Float(b)) | (&Float(b), &Int(a)) => a as f64 == b,
             _ => false,
         }
     }
 }
 
-pub fn eval(program: Ast, variables: &mut HashMap<Rc<String>, Value>) -> Value {
+/// A lexical scope: a small list of locally-bound variables plus an
+/// optional link to the scope it was created in. Looking up a variable
+/// walks from the innermost scope outwards, so calling a function no
+/// longer requires copying every variable visible at the call site —
+/// it just needs the handful it was actually called with.
+pub struct Scope {
+    parent: Option<Rc<Scope>>,
+    locals: RefCell<Vec<(Rc<String>, Value)>>,
+}
+
+impl Default for Scope {
+    fn default() -> Self {
+        Scope::new()
+    }
+}
+
+impl Scope {
+    /// A fresh top-level scope with no parent and no bindings.
+    pub fn new() -> Self {
+        Scope {
+            parent: None,
+            locals: RefCell::new(Vec::new()),
+        }
+    }
+
+    /// A scope nested directly inside `parent`, e.g. the body of a function
+    /// call, which should see `parent`'s bindings but not leak its own back
+    /// into it.
+    pub fn child(parent: Rc<Scope>) -> Self {
+        Scope {
+            parent: Some(parent),
+            locals: RefCell::new(Vec::new()),
+        }
+    }
+
+    fn get(&self, name: &str) -> Option<Value> {
+        for (k, v) in self.locals.borrow().iter().rev() {
+            if k.as_str() == name {
+                return Some(v.clone());
+            }
+        }
+
+        self.parent.as_ref().and_then(|parent| parent.get(name))
+    }
+
+    fn define(&self, name: Rc<String>, value: Value) {
+        self.locals.borrow_mut().push((name, value));
+    }
+}
+
+/// Controls how aggressively `optimize` is allowed to rewrite a program
+/// before it reaches `eval`.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum OptimizationLevel {
+    /// Leave the `Ast` untouched.
+    None,
+    /// Constant-fold calls to a small set of known-pure inbuilts, fold
+    /// literal `Define`s into the constants table, and drop dead
+    /// statements from function bodies.
+    Simple,
+    /// Everything `Simple` does, plus: treat *every* `InbuiltFunc` as pure,
+    /// and inline one level of constant-argument `Function` calls.
+    Full,
+}
+
+// Names we know don't have side effects and always return the same `Value`
+// for the same arguments, so folding a call to one of them at compile time
+// is safe even under `Simple`.
+const PURE_INBUILTS: &[&str] = &["add", "eq"];
+
+/// Rewrites `program` into an equivalent but cheaper `Ast`, using `constants`
+/// as the starting set of known bindings (e.g. the globals `eval` will be
+/// run with). Runs to a fixpoint: each pass may unlock further folds (a
+/// `Define` turning into a constant lets a later `Call` fold, which may
+/// shrink a function body enough for another `Define` to fold, and so on).
+pub fn optimize(program: Ast, constants: &HashMap<String, Value>, level: OptimizationLevel) -> Ast {
+    if level == OptimizationLevel::None {
+        return program;
+    }
+
+    let mut constants: HashMap<String, Value> = constants.clone();
+    let mut program = program;
+
+    loop {
+        let mut changed = false;
+        program = optimize_ast(program, &mut constants, level, &mut changed);
+
+        if !changed {
+            break;
+        }
+    }
+
+    program
+}
+
+fn optimize_ast(
+    node: Ast,
+    constants: &mut HashMap<String, Value>,
+    level: OptimizationLevel,
+    changed: &mut bool,
+) -> Ast {
+    match node {
+        Ast::Lit(Value::Function(params, body, captured)) => {
+            // A function body gets its own constants scope: parameters
+            // shadow any same-named outer constant for the duration of the
+            // body (a reference to `someval` inside `(\ (someval) ...)`
+            // must see the argument, not a constant folded from the
+            // enclosing scope), and a `Define` inside the body must not
+            // leak out to sibling/outer code once the body is done with it
+            // (`(f (\ () (= x 5) x) x)`'s trailing `x` is a different `x`).
+            // Snapshotting and restoring `constants` around the body keeps
+            // both cases from crossing the function's boundary.
+            let outer_constants = constants.clone();
+            for param in &params {
+                constants.remove(param);
+            }
+            let body = optimize_body(body, constants, level, changed);
+            *constants = outer_constants;
+            Ast::Lit(Value::Function(params, body, captured))
+        }
+        Ast::Lit(val) => Ast::Lit(val),
+        // Callable constants (`Function`/`InbuiltFunc`) are deliberately
+        // left as `Ast::Variable` rather than substituted: `try_fold_call`
+        // matches on the callee still being a `Variable` so it can look the
+        // name back up in `constants`, and a `Call` needs that to happen
+        // before the scalar-substitution case below ever touches it.
+        Ast::Variable(name)
+            if matches!(
+                constants.get(&name),
+                Some(Value::Function(..)) | Some(Value::InbuiltFunc(_))
+            ) =>
+        {
+            Ast::Variable(name)
+        }
+        Ast::Variable(name) => match constants.get(&name) {
+            Some(val) => {
+                *changed = true;
+                Ast::Lit(val.clone())
+            }
+            None => Ast::Variable(name),
+        },
+        Ast::Define(name, value) => {
+            let value = optimize_ast(*value, constants, level, changed);
+
+            if let Ast::Lit(ref val) = value {
+                constants.insert(name.clone(), val.clone());
+            }
+
+            Ast::Define(name, Box::new(value))
+        }
+        Ast::Call(func, arguments) => {
+            let func = optimize_ast(*func, constants, level, changed);
+            let arguments: Vec<Ast> = arguments
+                .into_iter()
+                .map(|arg| optimize_ast(arg, constants, level, changed))
+                .collect();
+
+            match try_fold_call(&func, &arguments, constants, level) {
+                Some(folded) => {
+                    *changed = true;
+                    Ast::Lit(folded)
+                }
+                None => Ast::Call(Box::new(func), arguments),
+            }
+        }
+    }
+}
+
+// Drops statements whose value can never be observed: a `Define` whose
+// value folded all the way down to an `Ast::Lit` has already been recorded
+// in `constants`, so it no longer needs to run, and the same is true of a
+// bare `Void`. A `Define` whose value *didn't* fold (e.g. it depends on a
+// parameter) still has to run — dropping it would leave later references
+// to that name as an unresolved `Variable`. The same goes for a `Define` of
+// a function literal: callable constants are never substituted into their
+// call sites, so the binding has to exist when `eval` runs. The last
+// statement is always kept since its value is the body's result.
+fn optimize_body(
+    body: Vec<Ast>,
+    constants: &mut HashMap<String, Value>,
+    level: OptimizationLevel,
+    changed: &mut bool,
+) -> Vec<Ast> {
+    let last = body.len().saturating_sub(1);
+    let mut out = Vec::with_capacity(body.len());
+
+    for (i, stmt) in body.into_iter().enumerate() {
+        let stmt = optimize_ast(stmt, constants, level, changed);
+
+        let is_dead = match &stmt {
+            Ast::Define(_, value) => {
+                matches!(**value, Ast::Lit(_))
+                    && !matches!(**value, Ast::Lit(Value::Function(..)))
+            }
+            Ast::Lit(Value::Void) => true,
+            _ => false,
+        };
+
+        if i != last && is_dead {
+            *changed = true;
+            continue;
+        }
+
+        out.push(stmt);
+    }
+
+    out
+}
+
+// If `func`/`arguments` form a call we can evaluate right now, does so and
+// returns the resulting `Value`. Only ever called with `arguments` that have
+// already been reduced as far as possible, so `Ast::Lit` is the only shape
+// worth checking for.
+fn try_fold_call(
+    func: &Ast,
+    arguments: &[Ast],
+    constants: &HashMap<String, Value>,
+    level: OptimizationLevel,
+) -> Option<Value> {
+    let callee_name = match func {
+        Ast::Variable(name) => name.as_str(),
+        _ => return None,
+    };
+
+    let literal_args: Vec<Value> = arguments
+        .iter()
+        .map(|arg| match arg {
+            Ast::Lit(val) => Some(val.clone()),
+            _ => None,
+        })
+        .collect::<Option<_>>()?;
+
+    match constants.get(callee_name)? {
+        Value::InbuiltFunc(f)
+            if level == OptimizationLevel::Full || PURE_INBUILTS.contains(&callee_name) =>
+        {
+            // A folded call that would actually fail (e.g. bad argument
+            // types) is left unfolded so the error surfaces from `eval` as
+            // usual, rather than aborting the optimization pass.
+            f(literal_args).ok()
+        }
+        Value::Function(params, body, captured) if level == OptimizationLevel::Full => {
+            if params.len() != literal_args.len() {
+                return None;
+            }
+
+            // Evaluate the body as-is with `eval` rather than recursing back
+            // into `optimize`: any calls it makes, to other user functions
+            // included, run to completion as part of that evaluation, and
+            // if any of them fails the call is left unfolded.
+            let scope = Rc::new(Scope::child(Rc::clone(captured)));
+            for (name, val) in params.iter().cloned().zip(literal_args) {
+                scope.define(Rc::new(name), val);
+            }
+
+            let mut out = Value::Void;
+            for stmt in body {
+                out = eval(stmt.clone(), &scope, None).ok()?;
+            }
+
+            Some(out)
+        }
+        _ => None,
+    }
+}
+
+pub fn eval(
+    program: Ast,
+    scope: &Rc<Scope>,
+    on_var: Option<&Resolver>,
+) -> Result<Value, EvalError> {
     use self::Ast::*;
     use self::Value::*;
 
     match program {
-        Lit(val) => val,
-        Variable(name) => match variables.get(&name) {
-            Some(v) => v.clone(),
-            _ => panic!("Variable does not exist: {}", &name),
-        },
+        // A lambda literal captures the environment it's evaluated in, so
+        // it keeps seeing those bindings however it's later called —
+        // including once it's been returned out of the call that defined
+        // it.
+        Lit(Function(params, body, _)) => Ok(Function(params, body, Rc::clone(scope))),
+        Lit(val) => Ok(val),
+        Variable(name) => {
+            if let Some(val) = on_var.and_then(|resolve| resolve(&name)) {
+                return Ok(val);
+            }
+
+            let value = scope.get(&name);
+            value.ok_or(EvalError::UnknownVariable(name))
+        }
         Call(func, arguments) => {
-            let func = eval(*func, variables);
+            let func = eval(*func, scope, on_var)?;
 
             match func {
-                Function(args, body) => {
-                    // Start a new scope, so all variables defined in the body of the
-                    // function don't leak into the surrounding scope.
-                    let mut new_scope = variables.clone();
-
+                Function(args, body, captured) => {
                     if arguments.len() != args.len() {
-                        println!("Called function with incorrect number of arguments (expected {}, got {})", args.len(), arguments.len());
+                        return Err(EvalError::ArityMismatch {
+                            expected: args.len(),
+                            got: arguments.len(),
+                        });
                     }
 
+                    // Start a new scope linked to the *captured* environment
+                    // (not the caller's), so the closure sees the bindings
+                    // visible where it was defined, and variables defined in
+                    // its body don't leak into the surrounding scope.
+                    let new_scope = Rc::new(Scope::child(captured));
+
                     for (name, val) in args.into_iter().zip(arguments) {
-                        let val = eval(val, variables);
-                        new_scope.insert(Rc::new(name), val);
+                        let val = eval(val, scope, on_var)?;
+                        new_scope.define(Rc::new(name), val);
                     }
 
                     let mut out = Void;
 
                     for stmt in body {
-                        out = eval(stmt, &mut new_scope);
+                        out = eval(stmt, &new_scope, on_var)?;
                     }
 
-                    out
+                    Ok(out)
                 }
-                InbuiltFunc(func) => func(
-                    arguments
+                InbuiltFunc(func) => {
+                    let args = arguments
                         .into_iter()
-                        .map(|ast| eval(ast, variables))
-                        .collect(),
-                ),
-                _ => panic!("Attempted to call a non-function"),
+                        .map(|ast| eval(ast, scope, on_var))
+                        .collect::<Result<Vec<_>, _>>()?;
+
+                    func(args)
+                }
+                _ => Err(EvalError::NotCallable),
             }
         }
         Define(name, value) => {
-            let value = eval(*value, variables);
+            let value = eval(*value, scope, on_var)?;
 
-            variables.insert(Rc::new(name), value);
+            scope.define(Rc::new(name), value);
 
-            Void
+            Ok(Void)
         }
     }
 }
 
+/// Evaluates `program`, panicking on any `EvalError`. The benches below care
+/// about interpreter throughput rather than error handling, so this keeps
+/// them from having to unwrap every call themselves.
+pub fn eval_unwrap(program: Ast, scope: &Rc<Scope>, on_var: Option<&Resolver>) -> Value {
+    eval(program, scope, on_var).expect("eval failed")
+}
+
 parser! {
     pub fn expr[I]()(I) -> Ast where [I: combine::Stream<Item = char>] {
         use combine::parser::char::*;
         use combine::*;
+        use std::rc::Rc;
 
         macro_rules! white {
             ($prs:expr) => {
@@ -114,10 +437,42 @@ parser! {
             white!(lambda),
             white!(between(char('('), char(')'), many::<Vec<_>, _>(ident()))),
             many::<Vec<_>, _>(expr()),
-        ).map(|(_, a, b)| Ast::Lit(::Value::Function(a, b)));
+        ).map(|(_, a, b)| Ast::Lit(::Value::Function(a, b, Rc::new(::Scope::new()))));
         let define = (white!(eq), ident(), expr()).map(|(_, a, b)| Ast::Define(a, Box::new(b)));
-        let lit_num = many1::<String, _>(digit())
-            .map(|i| Ast::Lit(::Value::Int(i.parse().expect("Parsing integer failed"))));
+        let lit_num = (
+            many1::<String, _>(digit()),
+            optional((char('.'), many1::<String, _>(digit())).map(|(_, frac)| frac)),
+            optional((
+                satisfy(|c| c == 'e' || c == 'E'),
+                optional(satisfy(|c| c == '+' || c == '-')),
+                many1::<String, _>(digit()),
+            )),
+        ).map(|(int_part, frac_part, exp_part)| {
+            // A `.` or an exponent is what distinguishes a `Float` literal
+            // from an `Int` one; without either we keep the cheaper `Int`.
+            let mut is_float = frac_part.is_some() || exp_part.is_some();
+            let mut text = int_part;
+
+            if let Some(frac) = frac_part {
+                text.push('.');
+                text.push_str(&frac);
+            }
+
+            if let Some((e, sign, digits)) = exp_part {
+                is_float = true;
+                text.push(e);
+                if let Some(sign) = sign {
+                    text.push(sign);
+                }
+                text.push_str(&digits);
+            }
+
+            if is_float {
+                Ast::Lit(::Value::Float(text.parse().expect("Parsing float failed")))
+            } else {
+                Ast::Lit(::Value::Int(text.parse().expect("Parsing integer failed")))
+            }
+        });
         let call = (expr(), many(expr())).map(|(func, args)| Ast::Call(Box::new(func), args));
 
         white!(choice!(
@@ -137,7 +492,7 @@ mod benches {
 
     use self::test::{black_box, Bencher};
 
-    use super::{eval, expr, Value};
+    use super::{eval_unwrap as eval, expr, EvalError, Resolver, Scope, Value};
     use std::rc::Rc;
 
     // First we need some helper functions. These are used with the `InbuiltFunc`
@@ -145,33 +500,47 @@ mod benches {
     // to the global namespace in Lua.
     //
     // This one simply sums the arguments.
-    fn add(variables: Vec<Value>) -> Value {
-        let mut out = 0u64;
+    fn add(variables: Vec<Value>) -> Result<Value, EvalError> {
+        let mut int_out = 0u64;
+        let mut float_out = 0.0f64;
+        let mut is_float = false;
 
         for v in variables {
             match v {
-                Value::Int(i) => out += i,
-                _ => println!("Tried to add a non-int"),
+                Value::Int(i) if is_float => float_out += i as f64,
+                Value::Int(i) => int_out += i,
+                Value::Float(f) => {
+                    if !is_float {
+                        is_float = true;
+                        float_out = int_out as f64;
+                    }
+                    float_out += f;
+                }
+                _ => return Err(EvalError::TypeError("add expects numeric arguments")),
             }
         }
 
-        Value::Int(out)
+        if is_float {
+            Ok(Value::Float(float_out))
+        } else {
+            Ok(Value::Int(int_out))
+        }
     }
 
     // This one checks the arguments for equality. I used `Void` to represent true
     // and `False` to represent false. This is mostly inspired by scheme, where
     // everything is true except for `#f`.
-    fn eq(mut variables: Vec<Value>) -> Value {
+    fn eq(mut variables: Vec<Value>) -> Result<Value, EvalError> {
         if let Some(last) = variables.pop() {
             for v in variables {
                 if v != last {
-                    return Value::False;
+                    return Ok(Value::False);
                 }
             }
 
-            Value::Void
+            Ok(Value::Void)
         } else {
-            Value::Void
+            Ok(Value::Void)
         }
     }
 
@@ -179,7 +548,7 @@ mod benches {
     // other programming language in existence. To do lazy evaluation you make
     // the `then` and `else` branches return functions and then call the
     // functions.
-    fn if_(variables: Vec<Value>) -> Value {
+    fn if_(variables: Vec<Value>) -> Result<Value, EvalError> {
         let mut iter = variables.into_iter();
         let (first, second, third) = (
             iter.next().expect("No condition for if"),
@@ -188,10 +557,10 @@ mod benches {
         );
         assert!(iter.next().is_none(), "Too many arguments supplied to `if`");
 
-        match first {
+        Ok(match first {
             Value::False => third,
             _ => second,
-        }
+        })
     }
 
     // Here are our test program strings. Our language looks a lot like Lisp,
@@ -296,6 +665,18 @@ mod benches {
 someval
 ";
 
+    // This string exercises the `Float` variant and the mixed `Int`/`Float`
+    // coercion in `add`, so we can see what the extra match arm in the hot
+    // `eval` loop costs versus the all-`Int` programs above.
+    const FLOAT_HEAVY: &str = r"
+    (= sum (\(a b c d e)
+      (add a b c d e)))
+    (sum 1.5 2.25 3 4.125 5.5)
+    (add 1 2.5)
+    (add 1.1 2.2 3.3 4.4 5.5)
+    (add 1e3 2.5e-1 3)
+    ";
+
     // Now we run the benchmarks. The parsing ones are very simple...
     #[bench]
     fn parse_deep_nesting(b: &mut Bencher) {
@@ -317,6 +698,11 @@ someval
         b.iter(|| black_box(expr().easy_parse(REAL_CODE)))
     }
 
+    #[bench]
+    fn parse_float_heavy(b: &mut Bencher) {
+        b.iter(|| black_box(::combine::many1::<Vec<_>, _>(expr()).easy_parse(FLOAT_HEAVY)))
+    }
+
     // We only test parsing for this one. We could test the speed of
     // evaluating these expressions too but I personally prefer to
     // keep the benchmarks few and representative.
@@ -340,75 +726,312 @@ someval
     // our testing code needs in order to run.
     #[bench]
     fn run_deep_nesting(b: &mut Bencher) {
-        use std::collections::HashMap;
-
         // This just returns a function so `((whatever))` (equivalent
         // to `(whatever())()`) does something useful. Specifically
         // it just returns itself. We try to do as little work as
         // possible here so that our benchmark is still testing the
         // interpreter and not this function.
-        fn callable(_: Vec<Value>) -> Value {
-            Value::InbuiltFunc(callable)
+        fn callable(_: Vec<Value>) -> Result<Value, EvalError> {
+            Ok(Value::InbuiltFunc(callable))
         }
 
-        let mut env = HashMap::new();
-        env.insert(Rc::new("test".to_owned()), Value::InbuiltFunc(callable));
+        let env = Rc::new(Scope::new());
+        env.define(Rc::new("test".to_owned()), Value::InbuiltFunc(callable));
 
         let (program, _) = expr().easy_parse(DEEP_NESTING).unwrap();
 
-        b.iter(|| black_box(eval(program.clone(), &mut env)));
+        b.iter(|| black_box(eval(program.clone(), &env, None)));
     }
 
     #[bench]
     fn run_real_code(b: &mut Bencher) {
-        use std::collections::HashMap;
+        let (program, _) = ::combine::many1::<Vec<_>, _>(expr())
+            .easy_parse(REAL_CODE)
+            .unwrap();
 
-        let mut env = HashMap::new();
+        b.iter(|| {
+            let env = Rc::new(Scope::new());
 
-        env.insert(Rc::new("eq".to_owned()), Value::InbuiltFunc(eq));
-        env.insert(Rc::new("add".to_owned()), Value::InbuiltFunc(add));
-        env.insert(Rc::new("if".to_owned()), Value::InbuiltFunc(if_));
+            env.define(Rc::new("eq".to_owned()), Value::InbuiltFunc(eq));
+            env.define(Rc::new("add".to_owned()), Value::InbuiltFunc(add));
+            env.define(Rc::new("if".to_owned()), Value::InbuiltFunc(if_));
 
+            for line in &program {
+                black_box(eval(line.clone(), &env, None));
+            }
+        });
+    }
+
+    // Same program, but `eq`/`add`/`if` are served through a `Resolver`
+    // instead of being pre-populated in the `Scope`, so we can see what the
+    // callback indirection costs versus a plain scope lookup.
+    #[bench]
+    fn run_real_code_via_resolver(b: &mut Bencher) {
         let (program, _) = ::combine::many1::<Vec<_>, _>(expr())
             .easy_parse(REAL_CODE)
             .unwrap();
 
+        let resolver: Resolver = Rc::new(|name: &str| match name {
+            "eq" => Some(Value::InbuiltFunc(eq)),
+            "add" => Some(Value::InbuiltFunc(add)),
+            "if" => Some(Value::InbuiltFunc(if_)),
+            _ => None,
+        });
+
         b.iter(|| {
-            let mut env = env.clone();
+            let env = Rc::new(Scope::new());
+
             for line in &program {
-                black_box(eval(line.clone(), &mut env));
+                black_box(eval(line.clone(), &env, Some(&resolver)));
             }
         });
     }
 
     #[bench]
     fn run_many_variables(b: &mut Bencher) {
-        use std::collections::HashMap;
-
         // This just takes anything and returns `Void`. We just
         // want a function that can take any number of arguments
         // but we don't want that function to do anything useful
         // since, again, the benchmark should be of the
         // interpreter's code.
-        fn ignore(_: Vec<Value>) -> Value {
-            Value::Void
+        fn ignore(_: Vec<Value>) -> Result<Value, EvalError> {
+            Ok(Value::Void)
         }
 
         let (program, _) = expr().easy_parse(MANY_VARIABLES).unwrap();
 
-        let mut env = HashMap::new();
+        let env = Rc::new(Scope::new());
 
-        env.insert(Rc::new("ignore".to_owned()), Value::InbuiltFunc(ignore));
+        env.define(Rc::new("ignore".to_owned()), Value::InbuiltFunc(ignore));
 
-        b.iter(|| black_box(eval(program.clone(), &mut env)));
+        b.iter(|| black_box(eval(program.clone(), &env, None)));
     }
 
     #[bench]
-    fn run_nested_func(b: &mut Bencher) {
-        use std::collections::HashMap;
+    fn run_float_heavy(b: &mut Bencher) {
+        let (program, _) = ::combine::many1::<Vec<_>, _>(expr())
+            .easy_parse(FLOAT_HEAVY)
+            .unwrap();
 
+        b.iter(|| {
+            let env = Rc::new(Scope::new());
+            env.define(Rc::new("add".to_owned()), Value::InbuiltFunc(add));
+
+            for line in &program {
+                black_box(eval(line.clone(), &env, None));
+            }
+        });
+    }
+
+    #[bench]
+    fn run_nested_func(b: &mut Bencher) {
         let (program, _) = expr().easy_parse(NESTED_FUNC).unwrap();
-        let mut env = HashMap::new();
-        b.iter(|| black_box(eval(program.clone(), &mut env)));
+        let env = Rc::new(Scope::new());
+        b.iter(|| black_box(eval(program.clone(), &env, None)));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{eval, expr, optimize, Ast, EvalError, OptimizationLevel, Scope, Value};
+    use combine::Parser;
+    use std::collections::HashMap;
+    use std::rc::Rc;
+
+    fn int(i: u64) -> Ast {
+        Ast::Lit(Value::Int(i))
+    }
+
+    fn var(name: &str) -> Ast {
+        Ast::Variable(name.to_string())
+    }
+
+    fn call(name: &str, arguments: Vec<Ast>) -> Ast {
+        Ast::Call(Box::new(var(name)), arguments)
+    }
+
+    fn define(name: &str, value: Ast) -> Ast {
+        Ast::Define(name.to_string(), Box::new(value))
+    }
+
+    fn add(variables: Vec<Value>) -> Result<Value, EvalError> {
+        let mut out = 0u64;
+        for v in variables {
+            match v {
+                Value::Int(i) => out += i,
+                _ => return Err(EvalError::TypeError("add expects Int arguments")),
+            }
+        }
+        Ok(Value::Int(out))
+    }
+
+    #[test]
+    fn folds_calls_to_pure_inbuilts() {
+        let mut constants = HashMap::new();
+        constants.insert("add".to_string(), Value::InbuiltFunc(add));
+
+        let program = call("add", vec![int(1), int(2)]);
+        let optimized = optimize(program, &constants, OptimizationLevel::Simple);
+
+        assert!(matches!(optimized, Ast::Lit(Value::Int(3))));
+    }
+
+    #[test]
+    fn folded_define_substitutes_later_references_and_is_dropped() {
+        // `(\() (= x 5) (add x 1))`: `x` folds to a constant, so the
+        // `Define` can be dropped and the later reference replaced, leaving
+        // a single-statement body.
+        let body = vec![
+            define("x", int(5)),
+            call("add", vec![var("x"), int(1)]),
+        ];
+        let program = Ast::Lit(Value::Function(vec![], body, Rc::new(Scope::new())));
+
+        let optimized = optimize(program, &HashMap::new(), OptimizationLevel::Simple);
+
+        match optimized {
+            Ast::Lit(Value::Function(_, body, _)) => {
+                assert_eq!(body.len(), 1);
+                assert!(matches!(
+                    &body[0],
+                    Ast::Call(_, args) if matches!(args[0], Ast::Lit(Value::Int(5)))
+                ));
+            }
+            _ => panic!("expected a Function literal"),
+        }
+    }
+
+    #[test]
+    fn body_local_define_does_not_leak_into_outer_constants() {
+        // `(f (\ () (= x 5) x) x)`: the `x` defined inside the lambda body
+        // is local to it. The trailing `x` passed to `f` is a different
+        // binding entirely and must not be folded to the body's `5`.
+        let inner_body = vec![define("x", int(5)), var("x")];
+        let program = call(
+            "f",
+            vec![
+                Ast::Lit(Value::Function(vec![], inner_body, Rc::new(Scope::new()))),
+                var("x"),
+            ],
+        );
+
+        let optimized = optimize(program, &HashMap::new(), OptimizationLevel::Simple);
+
+        match optimized {
+            Ast::Call(_, args) => {
+                assert!(matches!(&args[1], Ast::Variable(name) if name == "x"));
+            }
+            _ => panic!("expected a Call"),
+        }
+    }
+
+    #[test]
+    fn non_constant_define_is_kept() {
+        // `(\(x y) (= tmp (add x y)) tmp)`: `tmp` depends on parameters, so
+        // it never folds and the `Define` must survive rather than being
+        // dropped as dead.
+        let body = vec![
+            define("tmp", call("add", vec![var("x"), var("y")])),
+            var("tmp"),
+        ];
+        let program = Ast::Lit(Value::Function(
+            vec!["x".to_string(), "y".to_string()],
+            body,
+            Rc::new(Scope::new()),
+        ));
+
+        let optimized = optimize(program, &HashMap::new(), OptimizationLevel::Simple);
+
+        match optimized {
+            Ast::Lit(Value::Function(_, body, _)) => {
+                assert_eq!(body.len(), 2);
+                assert!(matches!(&body[0], Ast::Define(name, _) if name == "tmp"));
+            }
+            _ => panic!("expected a Function literal"),
+        }
+    }
+
+    #[test]
+    fn parameters_shadow_same_named_constants() {
+        // `(\(someval) (add someval someval))` with `someval` folded to a
+        // constant elsewhere must not have its own parameter replaced.
+        let mut constants = HashMap::new();
+        constants.insert("someval".to_string(), Value::Int(99));
+
+        let body = vec![call("add", vec![var("someval"), var("someval")])];
+        let program = Ast::Lit(Value::Function(
+            vec!["someval".to_string()],
+            body,
+            Rc::new(Scope::new()),
+        ));
+
+        let optimized = optimize(program, &constants, OptimizationLevel::Simple);
+
+        match optimized {
+            Ast::Lit(Value::Function(_, body, _)) => match &body[0] {
+                Ast::Call(_, args) => {
+                    assert!(matches!(args[0], Ast::Variable(ref n) if n == "someval"));
+                    assert!(matches!(args[1], Ast::Variable(ref n) if n == "someval"));
+                }
+                _ => panic!("expected a Call"),
+            },
+            _ => panic!("expected a Function literal"),
+        }
+
+        // The constant is untouched outside the function, so a sibling
+        // reference to the same name still folds.
+        let optimized = optimize(var("someval"), &constants, OptimizationLevel::Simple);
+        assert!(matches!(optimized, Ast::Lit(Value::Int(99))));
+    }
+
+    #[test]
+    fn inlines_one_level_of_constant_function_calls_at_full_level() {
+        // `double`'s captured scope needs to be a real one with `add` bound,
+        // since inlining evaluates its body as-is against that scope rather
+        // than against the optimizer's `constants` table.
+        let global_scope = Rc::new(Scope::new());
+        global_scope.define(Rc::new("add".to_string()), Value::InbuiltFunc(add));
+
+        let mut constants = HashMap::new();
+        constants.insert("add".to_string(), Value::InbuiltFunc(add));
+        constants.insert(
+            "double".to_string(),
+            Value::Function(
+                vec!["n".to_string()],
+                vec![call("add", vec![var("n"), var("n")])],
+                Rc::clone(&global_scope),
+            ),
+        );
+
+        let program = call("double", vec![int(4)]);
+        let optimized = optimize(program, &constants, OptimizationLevel::Full);
+
+        assert!(matches!(optimized, Ast::Lit(Value::Int(8))));
+    }
+
+    #[test]
+    fn closures_keep_working_after_their_defining_call_returns() {
+        // `make`'s call frame (where `x` lives) has long returned by the
+        // time `adder` is actually called; the closure still needs to see
+        // `x` from it.
+        const PROGRAM: &str = r"
+        (= make (\ (x) (\ (y) (add x y))))
+        (= adder (make 5))
+        (adder 3)
+        ";
+
+        let (program, _) = combine::many1::<Vec<_>, _>(expr())
+            .easy_parse(PROGRAM)
+            .unwrap();
+
+        let env = Rc::new(Scope::new());
+        env.define(Rc::new("add".to_string()), Value::InbuiltFunc(add));
+
+        let mut out = Value::Void;
+        for stmt in program {
+            out = eval(stmt, &env, None).unwrap();
+        }
+
+        assert!(matches!(out, Value::Int(8)));
     }
 }